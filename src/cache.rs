@@ -0,0 +1,507 @@
+//! A concurrent loading cache built on top of [`CacheEntry`](crate::CacheEntry).
+//!
+//! [`CacheEntry`](crate::CacheEntry) on its own requires the caller to check
+//! [`is_expired()`](crate::CacheEntry::is_expired) and re-insert a fresh entry
+//! by hand. [`XFetchCache`] wraps a sharded concurrent map and does this for
+//! you: [`get_with`](XFetchCache::get_with) returns the cached value, or runs
+//! the supplied loader and stores the result when the entry is missing or its
+//! probabilistic early expiration fires.
+
+use dashmap::DashMap;
+use rand::{thread_rng, Rng};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::CacheEntry;
+
+type RemovalListener<K, V> = dyn Fn(&K, &V) + Send + Sync;
+
+/// A concurrent, self-refreshing cache that applies XFetch probabilistic
+/// early expiration on every read.
+///
+/// Unlike a plain map, callers need not store values themselves: a miss or a
+/// probabilistic early-expiry decision transparently triggers `loader` in
+/// [`get_with`](XFetchCache::get_with). [`insert`](XFetchCache::insert) is
+/// available for storing a value directly. Because XFetch is lockless by
+/// design, each thread makes its own independent recompute decision unless
+/// [`with_single_flight`](XFetchCache::with_single_flight) is enabled.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use xfetch::XFetchCache;
+///
+/// let cache: XFetchCache<&str, u64> = XFetchCache::new().with_ttl(Duration::from_secs(60));
+/// let value = cache.get_with("apple", || 3);
+/// assert_eq!(value, 3);
+/// ```
+pub struct XFetchCache<K, V> {
+    map: DashMap<K, CacheEntry<V>>,
+    ttl: Option<Duration>,
+    single_flight: bool,
+    in_flight: DashMap<K, Arc<Mutex<()>>>,
+    #[cfg(feature = "async")]
+    in_flight_async: DashMap<K, Arc<tokio::sync::Mutex<()>>>,
+    reap_interval: Option<Duration>,
+    max_jitter: Duration,
+    removal_listener: Option<Arc<RemovalListener<K, V>>>,
+    delta_alpha: Option<f32>,
+}
+
+impl<K, V> Default for XFetchCache<K, V>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        XFetchCache {
+            map: DashMap::new(),
+            ttl: None,
+            single_flight: false,
+            in_flight: DashMap::new(),
+            #[cfg(feature = "async")]
+            in_flight_async: DashMap::new(),
+            reap_interval: None,
+            max_jitter: Duration::from_secs(0),
+            removal_listener: None,
+            delta_alpha: None,
+        }
+    }
+}
+
+impl<K, V> XFetchCache<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Create a new, empty cache.
+    ///
+    /// Without a call to [`with_ttl`](XFetchCache::with_ttl), entries stored
+    /// by `get_with` never expire, so once a key is cached `get_with` returns
+    /// it without running the loader again.
+    pub fn new() -> Self {
+        XFetchCache::default()
+    }
+
+    /// Set the time-to-live applied to every entry stored by this cache.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Deduplicate concurrent recomputes of the same key.
+    ///
+    /// With single-flight enabled, only one thread actually runs `loader`
+    /// for a given key while the others wait for its result, instead of
+    /// every thread making its own independent recompute decision.
+    pub fn with_single_flight(mut self) -> Self {
+        self.single_flight = true;
+        self
+    }
+
+    /// Smooth each entry's delta across recomputes with an exponential
+    /// moving average, instead of using the latest measurement on its own.
+    ///
+    /// See [`CacheEntryBuilder::with_delta_smoothing`](crate::CacheEntryBuilder::with_delta_smoothing)
+    /// for the blending formula. `alpha` of `0.3` is a reasonable default.
+    pub fn with_delta_smoothing(mut self, alpha: f32) -> Self {
+        self.delta_alpha = Some(alpha);
+        self
+    }
+
+    /// Enable a background reaper that periodically sweeps the cache,
+    /// dropping entries whose hard ttl has truly elapsed. Call
+    /// [`start_reaper`](XFetchCache::start_reaper) to actually spawn it.
+    ///
+    /// Cold keys are otherwise never swept, since XFetch only recomputes an
+    /// entry on access.
+    pub fn with_reap_interval(mut self, interval: Duration) -> Self {
+        self.reap_interval = Some(interval);
+        self
+    }
+
+    /// Set the maximum jitter applied to the reaper's schedule.
+    ///
+    /// Each sweep is scheduled `reap_interval` plus or minus a random
+    /// duration up to `max_jitter`, so that many cache instances in a fleet
+    /// don't all sweep at the same time. This is the same
+    /// anti-synchronization reasoning behind XFetch itself, applied to the
+    /// reaper.
+    pub fn with_max_jitter(mut self, max_jitter: Duration) -> Self {
+        self.max_jitter = max_jitter;
+        self
+    }
+
+    /// Register a callback invoked with the key and value of every entry the
+    /// reaper evicts.
+    pub fn with_removal_listener<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(&K, &V) + Send + Sync + 'static,
+    {
+        self.removal_listener = Some(Arc::new(listener));
+        self
+    }
+}
+
+impl<K, V> XFetchCache<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn fresh_entry<F>(&self, loader: F, delta_prev: Option<Duration>, ttl: Option<Duration>) -> CacheEntry<V>
+    where
+        F: FnOnce() -> V,
+    {
+        let mut builder = CacheEntry::new(loader);
+        if let (Some(alpha), Some(previous)) = (self.delta_alpha, delta_prev) {
+            builder = builder.with_delta_smoothing(alpha, previous);
+        }
+        if let Some(ttl) = ttl {
+            builder = builder.with_ttl(move |_| ttl);
+        }
+        builder.build()
+    }
+
+    fn get_with_ttl_internal<F>(&self, key: K, ttl: Option<Duration>, loader: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        let mut delta_prev = None;
+        if let Some(entry) = self.map.get(&key) {
+            if !entry.is_expired() {
+                return entry.get().clone();
+            }
+            delta_prev = Some(entry.delta());
+        }
+
+        if !self.single_flight {
+            let entry = self.fresh_entry(loader, delta_prev, ttl);
+            let value = entry.get().clone();
+            self.map.insert(key, entry);
+            return value;
+        }
+
+        let guard = self
+            .in_flight
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone();
+        let _permit = guard.lock().unwrap();
+
+        // Another thread may have refreshed the entry while we waited for the lock.
+        if let Some(entry) = self.map.get(&key) {
+            if !entry.is_expired() {
+                return entry.get().clone();
+            }
+            delta_prev = Some(entry.delta());
+        }
+
+        let entry = self.fresh_entry(loader, delta_prev, ttl);
+        let value = entry.get().clone();
+        self.map.insert(key.clone(), entry);
+        self.in_flight.remove(&key);
+        value
+    }
+
+    /// Return the cached value for `key`, recomputing it with `loader` when
+    /// the entry is missing or has probabilistically expired.
+    pub fn get_with<F>(&self, key: K, loader: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        self.get_with_ttl_internal(key, self.ttl, loader)
+    }
+
+    /// Like [`get_with`](XFetchCache::get_with), but overrides the ttl this
+    /// cache would otherwise apply for this one recompute.
+    ///
+    /// Lets the same value type have different lifetimes depending on
+    /// context, instead of always deriving the ttl from
+    /// [`with_ttl`](XFetchCache::with_ttl).
+    pub fn get_with_ttl<F>(&self, key: K, ttl: Duration, loader: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        self.get_with_ttl_internal(key, Some(ttl), loader)
+    }
+
+    fn insert_internal(&self, key: K, ttl: Option<Duration>, value: V) -> Option<V> {
+        let now = Instant::now();
+        let previous = self
+            .map
+            .get(&key)
+            .filter(|entry| !entry.is_hard_expired_at(now))
+            .map(|entry| entry.get().clone());
+        let mut builder = CacheEntry::new(|| value);
+        if let Some(ttl) = ttl {
+            builder = builder.with_ttl(move |_| ttl);
+        }
+        self.map.insert(key, builder.build());
+        previous
+    }
+
+    /// Insert `value` for `key` directly, bypassing the loader.
+    ///
+    /// Returns the previous value if it existed and had not yet expired (or
+    /// `None` if it was already expired or absent), so callers can detect
+    /// whether they displaced live data — useful for metrics or
+    /// write-coalescing logic layered on top of the cache.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.insert_internal(key, self.ttl, value)
+    }
+
+    /// Like [`insert`](XFetchCache::insert), but overrides the ttl this cache
+    /// would otherwise apply.
+    pub fn insert_with_ttl(&self, key: K, ttl: Duration, value: V) -> Option<V> {
+        self.insert_internal(key, Some(ttl), value)
+    }
+}
+
+impl<K, V> XFetchCache<K, V>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    fn reap_once(&self) {
+        let now = Instant::now();
+        self.map.retain(|key, entry| {
+            let expired = entry.is_hard_expired_at(now);
+            if expired {
+                if let Some(listener) = &self.removal_listener {
+                    listener(key, entry.get());
+                }
+            }
+            !expired
+        });
+    }
+
+    fn next_reap_delay(interval: Duration, max_jitter: Duration) -> Duration {
+        if max_jitter.is_zero() {
+            return interval;
+        }
+        let jitter = Duration::from_nanos(thread_rng().gen_range(0..=max_jitter.as_nanos() as u64));
+        if thread_rng().gen_bool(0.5) {
+            interval.saturating_add(jitter)
+        } else {
+            interval.saturating_sub(jitter)
+        }
+    }
+
+    /// Spawn the background reaper thread.
+    ///
+    /// Requires [`with_reap_interval`](XFetchCache::with_reap_interval) to
+    /// have been called. Each sweep walks the map with a `retain`-style pass
+    /// dropping entries whose hard ttl has truly elapsed, then reschedules
+    /// itself after `reap_interval` plus or minus
+    /// [`max_jitter`](XFetchCache::with_max_jitter).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `with_reap_interval` was not called.
+    pub fn start_reaper(self: &Arc<Self>) -> JoinHandle<()> {
+        let interval = self
+            .reap_interval
+            .expect("with_reap_interval must be set before starting the reaper");
+        let max_jitter = self.max_jitter;
+        let cache = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(Self::next_reap_delay(interval, max_jitter));
+            cache.reap_once();
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl<K, V> XFetchCache<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    async fn fresh_entry_async<F, Fut>(&self, loader: F, delta_prev: Option<Duration>) -> CacheEntry<V>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = V>,
+    {
+        let ttl = self.ttl;
+        let mut builder = CacheEntry::new_async(loader).await;
+        if let (Some(alpha), Some(previous)) = (self.delta_alpha, delta_prev) {
+            builder = builder.with_delta_smoothing(alpha, previous);
+        }
+        if let Some(ttl) = ttl {
+            builder = builder.with_ttl(move |_| ttl);
+        }
+        builder.build()
+    }
+
+    /// Async counterpart of [`get_with`](XFetchCache::get_with), for loaders
+    /// that recompute the value asynchronously. Requires the `async` cargo
+    /// feature.
+    pub async fn get_with_async<F, Fut>(&self, key: K, loader: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = V>,
+    {
+        let mut delta_prev = None;
+        if let Some(entry) = self.map.get(&key) {
+            if !entry.is_expired() {
+                return entry.get().clone();
+            }
+            delta_prev = Some(entry.delta());
+        }
+
+        if !self.single_flight {
+            let entry = self.fresh_entry_async(loader, delta_prev).await;
+            let value = entry.get().clone();
+            self.map.insert(key, entry);
+            return value;
+        }
+
+        let guard = self
+            .in_flight_async
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(())))
+            .clone();
+        let _permit = guard.lock().await;
+
+        // Another task may have refreshed the entry while we waited for the lock.
+        if let Some(entry) = self.map.get(&key) {
+            if !entry.is_expired() {
+                return entry.get().clone();
+            }
+            delta_prev = Some(entry.delta());
+        }
+
+        let entry = self.fresh_entry_async(loader, delta_prev).await;
+        let value = entry.get().clone();
+        self.map.insert(key.clone(), entry);
+        self.in_flight_async.remove(&key);
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn test_get_with_caches_value() {
+        let cache: XFetchCache<&str, u64> = XFetchCache::new();
+        let calls = AtomicUsize::new(0);
+        let load = || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            42
+        };
+        assert_eq!(cache.get_with("apple", load), 42);
+        assert_eq!(cache.get_with("apple", load), 42);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_get_with_per_key() {
+        let cache: XFetchCache<&str, u64> = XFetchCache::new();
+        assert_eq!(cache.get_with("apple", || 3), 3);
+        assert_eq!(cache.get_with("banana", || 2), 2);
+    }
+
+    #[test]
+    fn test_single_flight_dedupes_concurrent_loads() {
+        let cache = Arc::new(
+            XFetchCache::<&str, u64>::new()
+                .with_ttl(Duration::from_secs(60))
+                .with_single_flight(),
+        );
+        let calls = Arc::new(AtomicUsize::new(0));
+        let threads = 8;
+        let barrier = Arc::new(Barrier::new(threads));
+
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let cache = cache.clone();
+                let calls = calls.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    cache.get_with("apple", || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(20));
+                        7
+                    })
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 7);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_reaper_evicts_hard_expired_entries() {
+        let removed = Arc::new(AtomicUsize::new(0));
+        let removed_clone = removed.clone();
+        let cache = Arc::new(
+            XFetchCache::<&str, u64>::new()
+                .with_ttl(Duration::from_millis(30))
+                .with_reap_interval(Duration::from_millis(10))
+                .with_removal_listener(move |_key, _value| {
+                    removed_clone.fetch_add(1, Ordering::SeqCst);
+                }),
+        );
+        cache.get_with("apple", || 1);
+        let _handle = cache.start_reaper();
+
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(removed.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_delta_smoothing_uses_previous_measurement() {
+        let cache = XFetchCache::<&str, u64>::new()
+            .with_ttl(Duration::from_millis(1))
+            .with_delta_smoothing(0.5);
+
+        cache.get_with("apple", || 1);
+        thread::sleep(Duration::from_millis(10));
+        cache.get_with("apple", || {
+            thread::sleep(Duration::from_millis(20));
+            1
+        });
+
+        let entry = cache.map.get("apple").unwrap();
+        // blended ~= 0.5 * ~20ms + 0.5 * ~0ms
+        assert!(entry.delta() >= Duration::from_millis(5));
+        assert!(entry.delta() <= Duration::from_millis(15));
+    }
+
+    #[test]
+    fn test_get_with_ttl_overrides_cache_ttl() {
+        let cache: XFetchCache<&str, u64> = XFetchCache::new().with_ttl(Duration::MAX);
+        cache.get_with_ttl("apple", Duration::from_millis(1), || 3);
+
+        thread::sleep(Duration::from_millis(10));
+        let entry = cache.map.get("apple").unwrap();
+        assert!(entry.is_expired());
+    }
+
+    #[test]
+    fn test_insert_returns_previous_unexpired_value() {
+        let cache: XFetchCache<&str, u64> = XFetchCache::new().with_ttl(Duration::from_secs(60));
+
+        assert_eq!(cache.insert("apple", 1), None);
+        assert_eq!(cache.insert("apple", 2), Some(1));
+    }
+
+    #[test]
+    fn test_insert_with_ttl_ignores_expired_previous_value() {
+        let cache: XFetchCache<&str, u64> = XFetchCache::new();
+
+        assert_eq!(cache.insert_with_ttl("apple", Duration::from_millis(1), 1), None);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(cache.insert_with_ttl("apple", Duration::from_secs(60), 2), None);
+    }
+}
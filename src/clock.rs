@@ -0,0 +1,93 @@
+//! Clock abstraction used to make [`CacheEntry`](crate::CacheEntry) expiration
+//! deterministically testable.
+//!
+//! By default [`is_expired`](crate::CacheEntry::is_expired) reads the time
+//! from [`SystemClock`], which calls [`Instant::now()`], so time-based
+//! behaviour can only be exercised by sleeping. A
+//! [`CacheEntry`](crate::CacheEntry) built with a [`MockClock`] lets tests
+//! advance time arbitrarily instead.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+/// A source of monotonic time.
+///
+/// The default implementation, [`SystemClock`], is backed by
+/// [`Instant::now()`]. Supplying a [`MockClock`] instead lets tests assert
+/// expiration deterministically, without sleeping.
+pub trait Clock {
+    /// Return the current instant, as seen by this clock.
+    fn now(&self) -> Instant;
+}
+
+/// The default [`Clock`], backed by [`Instant::now()`].
+///
+/// This is a zero-sized type, so using it keeps the expiration check as
+/// cheap as calling [`Instant::now()`] directly.
+#[derive(Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A [`Clock`] whose time only advances when told to, for deterministic tests.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use xfetch::clock::{Clock, MockClock};
+///
+/// let clock = MockClock::new();
+/// let start = clock.now();
+/// clock.advance(Duration::from_secs(60));
+/// assert_eq!(clock.now(), start + Duration::from_secs(60));
+/// ```
+pub struct MockClock {
+    base: Instant,
+    offset: Cell<Duration>,
+}
+
+impl MockClock {
+    /// Create a new mock clock, anchored to the real current instant.
+    pub fn new() -> Self {
+        MockClock {
+            base: Instant::now(),
+            offset: Cell::new(Duration::from_secs(0)),
+        }
+    }
+
+    /// Advance the clock by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.offset.set(self.offset.get() + duration);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.base + self.offset.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock_advances_on_demand() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(clock.now(), start + Duration::from_secs(10));
+    }
+}
@@ -121,27 +121,36 @@
 //! [archive]: https://www.slideshare.net/RedisLabs/redisconf17-internet-archive-preventing-cache-stampede-with-redis-and-xfetch
 
 use rand::{distributions::OpenClosed01, thread_rng, Rng, RngCore};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
+mod cache;
+pub mod clock;
+
+pub use cache::XFetchCache;
+pub use clock::{Clock, SystemClock};
+
 const DEFAULT_BETA: f32 = 1.0;
 
 /// The builder for building [CacheEntry](struct.CacheEntry.html) with
 /// supplied parameters.
-pub struct CacheEntryBuilder<T> {
+pub struct CacheEntryBuilder<T, C = SystemClock> {
     value: T,
     delta: Duration,
     beta: f32,
     expiry: Option<Instant>,
+    tti: Option<Duration>,
+    clock: C,
 }
 
-impl<T> CacheEntryBuilder<T> {
+impl<T, C: Clock> CacheEntryBuilder<T, C> {
     /// Set the beta value.
     ///
     /// Beta value > `1.0` favors more eager early expiration, value < `1.0`
     /// favors lazier early expiration.
     ///
     /// The default value `1.0` is usually the optimal value for most use cases.
-    pub fn with_beta(mut self, beta: f32) -> CacheEntryBuilder<T> {
+    pub fn with_beta(mut self, beta: f32) -> CacheEntryBuilder<T, C> {
         self.beta = beta;
         self
     }
@@ -155,7 +164,7 @@ impl<T> CacheEntryBuilder<T> {
     ///
     /// The reference of the value returned by the recomputation function is
     /// passed to the closure.
-    pub fn with_delta<F>(mut self, f: F) -> CacheEntryBuilder<T>
+    pub fn with_delta<F>(mut self, f: F) -> CacheEntryBuilder<T, C>
     where
         F: FnOnce(&T) -> Duration,
     {
@@ -163,6 +172,21 @@ impl<T> CacheEntryBuilder<T> {
         self
     }
 
+    /// Blend the measured delta with `previous` using an exponential moving
+    /// average: `delta = alpha * measured + (1 - alpha) * previous`.
+    ///
+    /// A single delta sample is noisy: one unusually fast or slow
+    /// recomputation skews every future early-expiry decision for that key.
+    /// Smoothing across recomputes keeps the `delta * beta * -ln(rand)`
+    /// early-expiry window tracking the true long-run recompute cost instead
+    /// of oscillating. `alpha` of `0.3` is a reasonable default; higher
+    /// values favor the most recent measurement, lower values favor history.
+    pub fn with_delta_smoothing(mut self, alpha: f32, previous: Duration) -> CacheEntryBuilder<T, C> {
+        let blended = alpha * self.delta.as_secs_f32() + (1.0 - alpha) * previous.as_secs_f32();
+        self.delta = Duration::from_secs_f32(blended.max(0.0));
+        self
+    }
+
     /// Set the ttl.
     ///
     /// The reference of the value returned by the recomputation function is
@@ -170,22 +194,57 @@ impl<T> CacheEntryBuilder<T> {
     ///
     /// If the ttl is not set then the cache entry will become a eternal cache
     /// entry that will never expire.
-    pub fn with_ttl<F>(mut self, f: F) -> CacheEntryBuilder<T>
+    pub fn with_ttl<F>(mut self, f: F) -> CacheEntryBuilder<T, C>
     where
         F: FnOnce(&T) -> Duration,
     {
-        self.expiry = Some(Instant::now() + f(&self.value));
+        self.expiry = Some(self.clock.now() + f(&self.value));
         self
     }
 
+    /// Set the time-to-idle (sliding expiration).
+    ///
+    /// Unlike [`with_ttl`](CacheEntryBuilder::with_ttl), which is anchored at
+    /// creation time, the time-to-idle deadline is pushed back every time
+    /// [`get()`](CacheEntry::get) is called. The entry's effective deadline is
+    /// whichever of the ttl and tti deadlines comes first, and probabilistic
+    /// early expiration is computed against that nearest deadline.
+    ///
+    /// Combine with [`with_ttl`](CacheEntryBuilder::with_ttl) to cap how long
+    /// even a frequently accessed entry may live; use on its own to let an
+    /// entry live forever as long as it keeps being read.
+    pub fn with_tti(mut self, tti: Duration) -> CacheEntryBuilder<T, C> {
+        self.tti = Some(tti);
+        self
+    }
+
+    /// Use a custom [`Clock`] instead of the real monotonic clock.
+    ///
+    /// This is mainly useful in tests, to drive expiration deterministically
+    /// with a `MockClock` instead of sleeping.
+    pub fn with_clock<C2: Clock>(self, clock: C2) -> CacheEntryBuilder<T, C2> {
+        CacheEntryBuilder {
+            value: self.value,
+            delta: self.delta,
+            beta: self.beta,
+            expiry: self.expiry,
+            tti: self.tti,
+            clock,
+        }
+    }
+
     /// Return a new [CacheEntry](struct.CacheEntry.html) with the supplied
     /// parameters.
-    pub fn build(self) -> CacheEntry<T> {
+    pub fn build(self) -> CacheEntry<T, C> {
         CacheEntry {
             value: self.value,
             delta: self.delta,
             beta: self.beta,
             expiry: self.expiry,
+            tti: self.tti,
+            created_at: self.clock.now(),
+            last_access_nanos: AtomicU64::new(0),
+            clock: self.clock,
         }
     }
 }
@@ -209,19 +268,45 @@ impl<T> CacheEntryBuilder<T> {
 /// ```
 ///
 /// See the [module-level documentation](index.html) for more information.
-#[derive(Copy, Clone)]
-pub struct CacheEntry<T> {
+///
+/// Note that, unlike earlier versions, `CacheEntry` is no longer `Copy`: when
+/// a time-to-idle is set (see
+/// [`with_tti()`](struct.CacheEntryBuilder.html#method.with_tti)), `get()`
+/// needs to record the last access time, which rules out a bitwise copy. The
+/// last access time is stored as a nanosecond offset from `created_at` in an
+/// `AtomicU64` rather than a `Cell`, so that `CacheEntry` (and caches built on
+/// top of it) stay `Sync` and can be shared across threads.
+pub struct CacheEntry<T, C = SystemClock> {
     value: T,
     delta: Duration,
     beta: f32,
     expiry: Option<Instant>,
+    tti: Option<Duration>,
+    created_at: Instant,
+    last_access_nanos: AtomicU64,
+    clock: C,
+}
+
+impl<T: Clone, C: Clone> Clone for CacheEntry<T, C> {
+    fn clone(&self) -> Self {
+        CacheEntry {
+            value: self.value.clone(),
+            delta: self.delta,
+            beta: self.beta,
+            expiry: self.expiry,
+            tti: self.tti,
+            created_at: self.created_at,
+            last_access_nanos: AtomicU64::new(self.last_access_nanos.load(Ordering::Relaxed)),
+            clock: self.clock.clone(),
+        }
+    }
 }
 
-impl<T> CacheEntry<T> {
+impl<T> CacheEntry<T, SystemClock> {
     /// Return a new [CacheEntryBuilder](struct.CacheEntryBuilder.html).
     ///
     /// This method takes a closure which should return the value to be cached.
-    pub fn new<F>(f: F) -> CacheEntryBuilder<T>
+    pub fn new<F>(f: F) -> CacheEntryBuilder<T, SystemClock>
     where
         F: FnOnce() -> T,
     {
@@ -233,22 +318,84 @@ impl<T> CacheEntry<T> {
             delta: recompute_time,
             beta: DEFAULT_BETA,
             expiry: None,
+            tti: None,
+            clock: SystemClock,
         }
     }
 
-    fn is_expired_with_rng(&self, rng: &mut RngCore) -> bool {
-        match self.expiry {
-            Some(expiry) => {
-                let now = Instant::now();
+    /// Return a new [CacheEntryBuilder](struct.CacheEntryBuilder.html),
+    /// awaiting an asynchronous recomputation future instead of calling a
+    /// synchronous closure.
+    ///
+    /// The elapsed wall-clock time spent awaiting `f` is recorded as `delta`,
+    /// mirroring what [`new`](CacheEntry::new) does for synchronous closures.
+    /// This requires the `async` cargo feature.
+    #[cfg(feature = "async")]
+    pub async fn new_async<F, Fut>(f: F) -> CacheEntryBuilder<T, SystemClock>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = T>,
+    {
+        let start = Instant::now();
+        let value = f().await;
+        let recompute_time = start.elapsed();
+        CacheEntryBuilder {
+            value,
+            delta: recompute_time,
+            beta: DEFAULT_BETA,
+            expiry: None,
+            tti: None,
+            clock: SystemClock,
+        }
+    }
+}
+
+impl<T, C: Clock> CacheEntry<T, C> {
+    /// Return the entry's current effective deadline: whichever of the ttl
+    /// and tti deadlines is nearest, or `None` for an eternal entry.
+    fn deadline(&self) -> Option<Instant> {
+        let last_access = self.created_at
+            + Duration::from_nanos(self.last_access_nanos.load(Ordering::Relaxed));
+        let tti_deadline = self.tti.map(|tti| last_access + tti);
+        match (self.expiry, tti_deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Check whether the entry's deadline has truly elapsed as of `now`,
+    /// without the probabilistic early-expiry window.
+    ///
+    /// This is used by background sweepers (such as
+    /// [`XFetchCache`](crate::XFetchCache)'s reaper) that only want to evict
+    /// entries that are hard-expired, as opposed to
+    /// [`is_expired`](CacheEntry::is_expired)'s probabilistic early hits.
+    pub(crate) fn is_hard_expired_at(&self, now: Instant) -> bool {
+        matches!(self.deadline(), Some(deadline) if now >= deadline)
+    }
+
+    /// Check whether the cache has expired as of `now`, given a source of
+    /// randomness.
+    ///
+    /// This is the core primitive behind [`is_expired`](CacheEntry::is_expired);
+    /// it takes `now` explicitly so tests can assert expiration deterministically
+    /// at a given instant instead of relying on wall-clock sleeps.
+    pub fn is_expired_at(&self, now: Instant, rng: &mut RngCore) -> bool {
+        match self.deadline() {
+            Some(deadline) => {
                 let delta = self.delta.as_millis() as f32;
                 let rand: f32 = rng.sample(OpenClosed01);
                 let xfetch = Duration::from_millis((delta * self.beta * -rand.ln()).round() as u64);
-                (now + xfetch) >= expiry
+                (now + xfetch) >= deadline
             }
             None => false,
         }
     }
 
+    fn is_expired_with_rng(&self, rng: &mut RngCore) -> bool {
+        self.is_expired_at(self.clock.now(), rng)
+    }
+
     /// Check whether the cache has expired or not.
     ///
     /// With probabilstic early expiration, this method may return `true` before
@@ -259,14 +406,31 @@ impl<T> CacheEntry<T> {
 
     /// Check if the cache entry will never expire.
     ///
-    /// If the cache entry is created without setting time to expiration then it
-    /// is a eternal cache entry.
+    /// If the cache entry is created without setting time to expiration or
+    /// time-to-idle then it is a eternal cache entry.
     pub fn is_eternal(&self) -> bool {
-        self.expiry.is_none()
+        self.expiry.is_none() && self.tti.is_none()
+    }
+
+    /// Returns the delta (recomputation time) measured for this entry.
+    ///
+    /// Useful for passing into
+    /// [`with_delta_smoothing()`](struct.CacheEntryBuilder.html#method.with_delta_smoothing)
+    /// as `previous` when recomputing the entry.
+    pub fn delta(&self) -> Duration {
+        self.delta
     }
 
     /// Returns a reference of the contained value.
+    ///
+    /// If a time-to-idle is set (see
+    /// [`with_tti()`](struct.CacheEntryBuilder.html#method.with_tti)), this
+    /// refreshes the entry's last-access time, pushing its sliding deadline
+    /// back out.
     pub fn get(&self) -> &T {
+        let elapsed = self.clock.now().saturating_duration_since(self.created_at);
+        self.last_access_nanos
+            .store(elapsed.as_nanos() as u64, Ordering::Relaxed);
         &self.value
     }
 
@@ -285,9 +449,9 @@ mod tests {
     fn test_new_entry() {
         let entry = CacheEntry::new(|| ()).build();
         assert_eq!(*entry.get(), ());
-        assert_eq!(entry.into_inner(), ());
         assert!(entry.is_eternal());
         assert_eq!(entry.beta, DEFAULT_BETA);
+        assert_eq!(entry.into_inner(), ());
     }
 
     #[test]
@@ -325,4 +489,68 @@ mod tests {
             .build();
         assert!(!entry.is_expired_with_rng(&mut max));
     }
+
+    #[test]
+    fn test_is_expired_at_with_mock_clock() {
+        use crate::clock::MockClock;
+
+        let clock = MockClock::new();
+        let mut max = StepRng::new(!0, 0);
+        let entry = CacheEntry::new(|| ())
+            .with_delta(|_| Duration::from_secs(10))
+            .with_clock(clock)
+            .with_ttl(|_| Duration::from_secs(120))
+            .build();
+
+        assert!(!entry.is_expired_at(entry.clock.now(), &mut max));
+        let later = entry.clock.now() + Duration::from_secs(130);
+        assert!(entry.is_expired_at(later, &mut max));
+    }
+
+    #[test]
+    fn test_tti_resets_on_get() {
+        use crate::clock::MockClock;
+
+        let clock = MockClock::new();
+        let entry = CacheEntry::new(|| ())
+            .with_delta(|_| Duration::from_secs(0))
+            .with_clock(clock)
+            .with_tti(Duration::from_secs(60))
+            .build();
+
+        assert!(!entry.is_eternal());
+        entry.clock.advance(Duration::from_secs(59));
+        entry.get();
+        entry.clock.advance(Duration::from_secs(59));
+        assert!(!entry.is_expired());
+        entry.clock.advance(Duration::from_secs(2));
+        assert!(entry.is_expired());
+    }
+
+    #[test]
+    fn test_ttl_and_tti_use_nearest_deadline() {
+        use crate::clock::MockClock;
+
+        let clock = MockClock::new();
+        let entry = CacheEntry::new(|| ())
+            .with_delta(|_| Duration::from_secs(0))
+            .with_clock(clock)
+            .with_ttl(|_| Duration::from_secs(120))
+            .with_tti(Duration::from_secs(10))
+            .build();
+
+        // Idle past the tti deadline, well inside the ttl deadline.
+        entry.clock.advance(Duration::from_secs(11));
+        assert!(entry.is_expired());
+    }
+
+    #[test]
+    fn test_delta_smoothing_blends_with_previous() {
+        let entry = CacheEntry::new(|| ())
+            .with_delta(|_| Duration::from_secs(10))
+            .with_delta_smoothing(0.3, Duration::from_secs(20))
+            .build();
+        // 0.3 * 10 + 0.7 * 20 = 17
+        assert_eq!(entry.delta(), Duration::from_secs(17));
+    }
 }